@@ -6,14 +6,49 @@ use alloc::vec::Vec;
 #[derive(Debug, Copy, Clone)]
 pub enum NP_Size {
     U32,
-    U16
+    U16,
+    VarInt
 }
 
-#[derive(Debug)]
+/// Reports the outgoing address-valued fields of a pointer reachable from the root
+/// HEAD, so `promote_to_u32` can rewrite them to point at their relocated home.
+/// Mirrors the `children` callback used by `compact`:
+/// `(field_offset, addr, kind, span_len)` describes one outgoing pointer field. A
+/// field whose address is null (0) must be omitted from the returned list entirely,
+/// not included with a placeholder address -- both `compact` and `promote_to_u32`
+/// key patched fields off `field_offset`, not position, so this is safe to do.
+pub type NP_GraphWalker = alloc::boxed::Box<dyn Fn(&NP_Memory, u32, &NP_PtrKinds, u32) -> Vec<(usize, u32, NP_PtrKinds, u32)>>;
+
 pub struct NP_Memory {
     bytes: UnsafeCell<Vec<u8>>,
-    
-    pub size: NP_Size
+
+    // Some for a buffer built by `from_shared` on a refcounted, cheaply-cloneable
+    // `bytes::Bytes` (`freeze` consumes the buffer instead); `bytes` above is then unused and all
+    // reads are served from here instead. Buffers in this state are read-only.
+    #[cfg(feature = "bytes")]
+    shared: UnsafeCell<Option<bytes::Bytes>>,
+
+    // segregated free lists: free_list[size_class] holds every known (addr, len) span
+    // whose len falls in that class, where size_class is roughly log2(len).
+    free_list: UnsafeCell<Vec<Vec<(u32, u32)>>>,
+
+    // schema-aware graph walker used to widen pointers when a U16 buffer is about to
+    // outgrow MAX_SIZE_SMALL; None means auto promotion is disabled and malloc just
+    // fails once the buffer is full, as before.
+    auto_promote: Option<NP_GraphWalker>,
+
+    size: UnsafeCell<NP_Size>
+}
+
+// manual impl: `auto_promote`'s boxed closure has no useful Debug representation
+impl core::fmt::Debug for NP_Memory {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("NP_Memory")
+            .field("bytes", &self.current_bytes())
+            .field("size", &self.size())
+            .field("auto_promote", &self.auto_promote.is_some())
+            .finish()
+    }
 }
 
 const MAX_SIZE_LARGE: usize = core::u32::MAX as usize;
@@ -25,21 +60,560 @@ impl<'a> NP_Memory {
     pub fn existing(bytes: Vec<u8>) -> Self {
 
         let size = bytes[1];
-        
+
         NP_Memory {
             bytes: UnsafeCell::new(bytes),
-            size: if size == 0 {
-                NP_Size::U32
-            } else {
-                NP_Size::U16
+            #[cfg(feature = "bytes")]
+            shared: UnsafeCell::new(None),
+            free_list: UnsafeCell::new(Vec::new()),
+            auto_promote: None,
+            size: UnsafeCell::new(match size {
+                0 => NP_Size::U32,
+                1 => NP_Size::U16,
+                _ => NP_Size::VarInt
+            })
+        }
+    }
+
+    /// Build a read-only buffer backed directly by a refcounted `bytes::Bytes`, with
+    /// no copy of the underlying data. Callers that already hold a `Bytes` (e.g. from
+    /// a network read) can hand it here instead of going through `existing` with an
+    /// owned `Vec<u8>`. Mutating calls (`malloc`, `free`, `write_bytes`, ...) on a
+    /// buffer built this way return `NP_Error`.
+    #[cfg(feature = "bytes")]
+    pub fn from_shared(data: bytes::Bytes) -> Self {
+
+        let size = data[1];
+
+        NP_Memory {
+            bytes: UnsafeCell::new(Vec::new()),
+            shared: UnsafeCell::new(Some(data)),
+            free_list: UnsafeCell::new(Vec::new()),
+            auto_promote: None,
+            size: UnsafeCell::new(match size {
+                0 => NP_Size::U32,
+                1 => NP_Size::U16,
+                _ => NP_Size::VarInt
+            })
+        }
+    }
+
+    /// Convert this buffer into a refcounted, cheaply-cloneable `bytes::Bytes`. Once
+    /// frozen it can be `clone()`d and `slice()`d across threads/tasks for free; the
+    /// tradeoff is that the buffer becomes read-only (see `from_shared`).
+    #[cfg(feature = "bytes")]
+    pub fn freeze(self) -> bytes::Bytes {
+        match self.shared.into_inner() {
+            Some(shared) => shared,
+            None => bytes::Bytes::from(self.bytes.into_inner())
+        }
+    }
+
+    #[cfg(feature = "bytes")]
+    fn is_frozen(&self) -> bool {
+        unsafe { &*self.shared.get() }.is_some()
+    }
+
+    #[cfg(not(feature = "bytes"))]
+    fn is_frozen(&self) -> bool {
+        false
+    }
+
+    /// Every call that mutates buffer contents goes through this first so a frozen
+    /// (shared/read-only) buffer fails loudly instead of silently no-op'ing or
+    /// panicking on the placeholder `Vec` left behind by `from_shared`.
+    fn ensure_mutable(&self) -> core::result::Result<(), NP_Error> {
+        if self.is_frozen() {
+            return Err(NP_Error::new("Buffer is frozen (shared, read-only); cannot mutate!"));
+        }
+        Ok(())
+    }
+
+    // unified read view over whichever backing this buffer currently has
+    #[cfg(feature = "bytes")]
+    fn current_bytes(&self) -> &[u8] {
+        let shared = unsafe { &*self.shared.get() };
+        match shared {
+            Some(data) => &data[..],
+            None => unsafe { &*self.bytes.get() }
+        }
+    }
+
+    #[cfg(not(feature = "bytes"))]
+    fn current_bytes(&self) -> &[u8] {
+        unsafe { &*self.bytes.get() }
+    }
+
+    /// Current addressing mode. `U16` buffers may flip to `U32` mid-flight if built by
+    /// `new_with_auto_promote` and a `malloc` would otherwise overflow.
+    pub fn size(&self) -> NP_Size {
+        unsafe { *self.size.get() }
+    }
+
+    // width, in bytes, of a pointer of `kind` under a given addressing mode
+    fn ptr_size_for(size: NP_Size, kind: &NP_PtrKinds) -> u32 {
+        match size {
+            NP_Size::U32 => {
+                match kind {
+                    NP_PtrKinds::None                                     =>   { 0u32 },
+                    NP_PtrKinds::Standard  { addr: _ }                   =>    { 4u32 },
+                    NP_PtrKinds::MapItem   { addr: _, key: _,  next: _ } =>    { 12u32 },
+                    NP_PtrKinds::TableItem { addr: _, i:_ ,    next: _ } =>    { 9u32 },
+                    NP_PtrKinds::ListItem  { addr: _, i:_ ,    next: _ } =>    { 10u32 }
+                }
+            },
+            NP_Size::U16 => {
+                match kind {
+                    NP_PtrKinds::None                                     =>   { 0u32 },
+                    NP_PtrKinds::Standard  { addr: _ }                   =>    { 2u32 },
+                    NP_PtrKinds::MapItem   { addr: _, key: _,  next: _ } =>    { 6u32 },
+                    NP_PtrKinds::TableItem { addr: _, i:_ ,    next: _ } =>    { 5u32 },
+                    NP_PtrKinds::ListItem  { addr: _, i:_ ,    next: _ } =>    { 6u32 }
+                }
+            },
+            // `blank_ptr_bytes` reserves every VarInt address field at its
+            // worst-case width up front (see that function) so a slot never has to
+            // relocate just because the address written into it grows. The width
+            // reported here has to match that reservation -- not the tight encoding
+            // of whatever address happens to be in `kind` right now -- or layout
+            // arithmetic built on this (field offsets, span advancement, `compact`'s
+            // root-span sizing) would disagree with what's actually on disk.
+            NP_Size::VarInt => {
+                Self::field_layout(kind).iter().fold(0u32, |sum, &(is_address, _w16, w32)| {
+                    sum + if is_address { Self::varint_max_len() } else { w32 }
+                })
+            }
+        }
+    }
+
+    // A pointer kind's own on-disk fields, in declaration order, as
+    // `(is_address, width_u16, width_u32)`. `is_address` marks fields that hold a
+    // relocatable address (and so scale with the addressing mode); the rest --
+    // `TableItem`/`ListItem`'s inline `i` index -- are plain values that keep the
+    // same width regardless of mode. Used by `promote_to_u32` to rebuild a node at
+    // its new, wider layout without disturbing the fields that don't move.
+    fn field_layout(kind: &NP_PtrKinds) -> Vec<(bool, u32, u32)> {
+        match kind {
+            NP_PtrKinds::None                                     =>   { Vec::new() },
+            NP_PtrKinds::Standard  { addr: _ }                   =>    { alloc::vec![(true, 2, 4)] },
+            NP_PtrKinds::MapItem   { addr: _, key: _,  next: _ } =>    { alloc::vec![(true, 2, 4), (true, 2, 4), (true, 2, 4)] },
+            NP_PtrKinds::TableItem { addr: _, i:_ ,    next: _ } =>    { alloc::vec![(true, 2, 4), (false, 1, 1), (true, 2, 4)] },
+            NP_PtrKinds::ListItem  { addr: _, i:_ ,    next: _ } =>    { alloc::vec![(true, 2, 4), (false, 2, 2), (true, 2, 4)] }
+        }
+    }
+
+    // number of bytes an unsigned LEB128 varint needs to hold `value`: 7 data bits
+    // per byte, high bit set on every byte but the last
+    fn varint_len(value: u32) -> u32 {
+        let mut v = value;
+        let mut len = 1u32;
+        while v >= 0x80 {
+            v >>= 7;
+            len += 1;
+        }
+        len
+    }
+
+    // worst-case width (in bytes) a VarInt address field can ever need -- the widest
+    // a u32 LEB128 encoding gets. A fresh pointer slot reserves this up front (see
+    // `blank_ptr_bytes`) so it never has to relocate the slot just because the
+    // address that ends up there is bigger than the all-zero value it started as.
+    fn varint_max_len() -> u32 {
+        Self::varint_len(core::u32::MAX)
+    }
+
+    // a blank (all-zero-valued) VarInt field padded out to exactly `width` bytes
+    // with continuation bytes, so it decodes back to `(0, width)` -- same shape
+    // `encode_varint_into_width(0, width)` produces, without needing a Result here
+    fn blank_varint_field(width: u32) -> Vec<u8> {
+        let mut out = alloc::vec![0x80u8; width as usize];
+        if let Some(last) = out.last_mut() {
+            *last = 0;
+        }
+        out
+    }
+
+    fn encode_varint(value: u32) -> Vec<u8> {
+        let mut v = value;
+        let mut out = Vec::new();
+        loop {
+            let mut byte = (v & 0x7F) as u8;
+            v >>= 7;
+            if v != 0 {
+                byte |= 0x80;
+            }
+            out.push(byte);
+            if v == 0 {
+                break;
+            }
+        }
+        out
+    }
+
+    /// Decode an unsigned LEB128 varint starting at `bytes[0]`, returning the decoded
+    /// value and how many bytes it occupied (including any zero-value continuation
+    /// padding produced by `encode_varint_into_width`). Errors on corrupt input that
+    /// never terminates -- a u32 never needs more than 5 LEB128 bytes, and shifting
+    /// a 6th continuation byte's bits into place would overflow the shift amount.
+    pub fn decode_varint(bytes: &[u8]) -> core::result::Result<(u32, usize), NP_Error> {
+        let mut result: u32 = 0;
+        let mut shift = 0u32;
+        let mut consumed = 0usize;
+
+        for &b in bytes.iter() {
+            if consumed >= Self::varint_max_len() as usize {
+                return Err(NP_Error::new("Corrupt VarInt: no terminating byte within 5 bytes!"));
+            }
+            consumed += 1;
+            result |= ((b & 0x7F) as u32) << shift;
+            if b & 0x80 == 0 {
+                return Ok((result, consumed));
+            }
+            shift += 7;
+        }
+
+        Err(NP_Error::new("Corrupt VarInt: buffer ended before a terminating byte!"))
+    }
+
+    // encode `value` so it occupies exactly `width` bytes, padding with continuation
+    // bytes (high bit set, zero payload) when the natural encoding is shorter so an
+    // in-place rewrite never changes a VarInt pointer slot's on-disk width. Errors if
+    // `value` genuinely needs more than `width` bytes -- the caller (which knows the
+    // surrounding layout) has to relocate the slot via `free`/`malloc` first.
+    fn encode_varint_into_width(value: u32, width: usize) -> core::result::Result<Vec<u8>, NP_Error> {
+        let mut bytes = Self::encode_varint(value);
+
+        if bytes.len() > width {
+            return Err(NP_Error::new("VarInt address grew past its reserved width; relocate this pointer before writing!"));
+        }
+
+        while bytes.len() < width {
+            let last = bytes.len() - 1;
+            bytes[last] |= 0x80;
+            bytes.push(0);
+        }
+
+        Ok(bytes)
+    }
+
+    /// Read a VarInt-encoded pointer address starting at `address`, returning the
+    /// decoded value and the number of bytes it occupies on disk. Mirrors `get_N_bytes`
+    /// for the fixed-width modes, except the width isn't known ahead of the read.
+    pub fn get_var_addr(&self, address: usize) -> Option<(u32, usize)> {
+        if address == 0 {
+            return None;
+        }
+
+        let self_bytes = self.current_bytes();
+
+        if address >= self_bytes.len() {
+            return None;
+        }
+
+        Self::decode_varint(&self_bytes[address..]).ok()
+    }
+
+    /// The buffer's root HEAD: the address of the top-level value, stored right after
+    /// the protocol version and size-key bytes. `0` means the buffer is empty. Kept a
+    /// fixed 4-byte `u32` even under `VarInt` addressing, same as `U32`, so the header
+    /// never needs to relocate as the root value grows -- only pointers inside the
+    /// data region are variable-width.
+    pub fn root_addr(&self) -> u32 {
+        let self_bytes = self.current_bytes();
+        match self.size() {
+            NP_Size::U32 | NP_Size::VarInt => u32::from_be_bytes([self_bytes[2], self_bytes[3], self_bytes[4], self_bytes[5]]),
+            NP_Size::U16 => u16::from_be_bytes([self_bytes[2], self_bytes[3]]) as u32
+        }
+    }
+
+    /// Widen every pointer reachable from the root HEAD from their current U16 field
+    /// widths to U32, rewriting downstream addresses as it goes, then flip this
+    /// buffer's addressing mode over to `NP_Size::U32`. Called automatically by
+    /// `malloc` when `auto_promote` is set and a U16 buffer is about to run out of
+    /// room; can also be called directly to promote ahead of time.
+    pub fn promote_to_u32(&self) -> core::result::Result<(), NP_Error> {
+
+        self.ensure_mutable()?;
+
+        match self.size() {
+            NP_Size::U32 => return Ok(()),
+            NP_Size::U16 => {},
+            // every width calculation below assumes the buffer is currently U16 --
+            // a VarInt buffer's pointers are already variable-width and don't need
+            // (or support) this kind of promotion
+            NP_Size::VarInt => return Err(NP_Error::new("Cannot promote buffer: only U16 buffers support promotion to U32!"))
+        }
+
+        let walker = match &self.auto_promote {
+            Some(w) => w,
+            None => return Err(NP_Error::new("Cannot promote buffer: no graph walker configured!"))
+        };
+
+        let root_addr = self.root_addr();
+        let root_kind = NP_PtrKinds::Standard { addr: root_addr };
+        let root_span = Self::ptr_size_for(NP_Size::U16, &root_kind);
+
+        // mark phase, identical shape to compact()'s: discover every live node and the
+        // pointer fields hanging off it, widths still measured under U16
+        let mut visited: Vec<u32> = Vec::new();
+        let mut order: Vec<(u32, NP_PtrKinds, u32)> = Vec::new(); // (addr, kind, old U16 span_len)
+        let mut fields_of: Vec<(u32, Vec<(usize, u32, NP_PtrKinds, u32)>)> = Vec::new();
+
+        let mut frontier: Vec<(u32, NP_PtrKinds, u32)> = Vec::new();
+        if root_addr != 0 {
+            frontier.push((root_addr, root_kind, root_span));
+        }
+
+        while let Some((addr, kind, span_len)) = frontier.pop() {
+            if addr == 0 || visited.contains(&addr) {
+                continue;
+            }
+            visited.push(addr);
+
+            let node_fields = walker(self, addr, &kind, span_len);
+            for &(_, child_addr, ref child_kind, child_span) in node_fields.iter() {
+                if child_addr != 0 && !visited.contains(&child_addr) {
+                    frontier.push((child_addr, child_kind.clone(), child_span));
+                }
+            }
+            order.push((addr, kind, span_len));
+            fields_of.push((addr, node_fields));
+        }
+
+        // sweep phase: lay every live node back-to-back after a U32-width header,
+        // widening each node's own pointer fields from U16 to U32 while keeping any
+        // plain fields (e.g. TableItem/ListItem's `i`) and trailing inline value
+        // payload the same width they already had -- `span_len` from the mark phase
+        // covers the pointer fields plus whatever payload follows them, same as
+        // compact()'s node spans
+        let header_len = 2 + Self::ptr_size_for(NP_Size::U32, &NP_PtrKinds::Standard { addr: 0 }) as usize;
+
+        let mut relocated: Vec<(u32, u32)> = Vec::new(); // old addr -> new addr
+        let mut new_spans: Vec<u32> = Vec::new();
+        let mut offset = header_len as u32;
+        for &(addr, ref kind, old_span) in order.iter() {
+            let old_kind_width = Self::ptr_size_for(NP_Size::U16, kind);
+            let new_kind_width = Self::ptr_size_for(NP_Size::U32, kind);
+            let trailing = old_span.saturating_sub(old_kind_width);
+            let new_span = new_kind_width + trailing;
+            relocated.push((addr, offset));
+            new_spans.push(new_span);
+            offset += new_span;
+        }
+
+        if (offset as usize) > MAX_SIZE_LARGE {
+            return Err(NP_Error::new("Buffer too large to promote to U32 addressing!"));
+        }
+
+        let new_addr_of = |addr: u32| -> u32 {
+            if addr == 0 {
+                return 0;
+            }
+            relocated.iter().find(|(old, _)| *old == addr).map(|(_, new)| *new).unwrap_or(0)
+        };
+
+        // build the new buffer: copy each node's plain fields and trailing inline
+        // value payload over verbatim (widened layout aside, their bytes don't
+        // change), leaving address fields zeroed for now -- those are patched below
+        // once every node's new address is known
+        let self_bytes = unsafe { &*self.bytes.get() };
+        let mut new_buffer: Vec<u8> = Vec::with_capacity(offset as usize);
+        new_buffer.push(self_bytes[0]);
+        new_buffer.push(0); // size key: 0 == U32
+        new_buffer.extend(0u32.to_be_bytes().to_vec());
+
+        // each node's offset_map (old U16 offset -> new U32 offset, address fields
+        // only) is built once here and reused by the patch pass below instead of
+        // recomputing field_layout a second time per node
+        let mut offset_maps: Vec<Vec<(usize, usize)>> = Vec::with_capacity(order.len());
+
+        for (i, &(addr, ref kind, old_span)) in order.iter().enumerate() {
+            let new_span = new_spans[i];
+            let mut node_bytes = alloc::vec![0u8; new_span as usize];
+
+            let layout = Self::field_layout(kind);
+            let mut offset_map: Vec<(usize, usize)> = Vec::new();
+            let mut old_off = 0usize;
+            let mut new_off = 0usize;
+            for &(is_address, w16, w32) in layout.iter() {
+                if is_address {
+                    offset_map.push((old_off, new_off));
+                } else {
+                    let start = addr as usize + old_off;
+                    node_bytes[new_off..new_off + w16 as usize]
+                        .copy_from_slice(&self_bytes[start..start + w16 as usize]);
+                }
+                old_off += w16 as usize;
+                new_off += w32 as usize;
+            }
+
+            // trailing inline value payload (anything past the kind's own pointer
+            // fields) carries over unchanged, just shifted to sit after the now-wider
+            // pointer portion
+            let trailing = (old_span as usize).saturating_sub(old_off);
+            if trailing > 0 {
+                let start = addr as usize + old_off;
+                node_bytes[new_off..new_off + trailing]
+                    .copy_from_slice(&self_bytes[start..start + trailing]);
+            }
+
+            new_buffer.extend(node_bytes);
+            offset_maps.push(offset_map);
+        }
+
+        // patch every relocated node's address fields, now U32-wide. The walker
+        // (per its documented convention, see `NP_GraphWalker`) omits fields whose
+        // address is null, so `node_fields` can hold fewer entries than the kind has
+        // address fields -- matching them up positionally against a freshly built
+        // U32 layout would silently write a live field's address into a neighboring
+        // field's slot. Map each field's own old (U16) `field_offset` to its new
+        // (U32) offset instead, the same way `compact` keys off `field_offset`
+        // directly (it doesn't need the map since compact doesn't widen fields).
+        for ((&(addr, _, _), &(_, ref node_fields)), offset_map) in order.iter().zip(fields_of.iter()).zip(offset_maps.iter()) {
+            let new_base = new_addr_of(addr) as usize;
+
+            for &(field_offset, child_addr, _, _) in node_fields.iter() {
+                let new_child = new_addr_of(child_addr);
+                // a `field_offset` that doesn't match any address field in this
+                // kind's own layout means the walker disagrees with `field_layout`
+                // about this node's shape -- fail loudly rather than falling back to
+                // the stale U16 offset and silently writing into the wrong field
+                let new_field_off = offset_map.iter()
+                    .find(|&&(old, _)| old == field_offset)
+                    .map(|&(_, new)| new)
+                    .ok_or_else(|| NP_Error::new("Graph walker reported a field_offset that doesn't match this pointer kind's layout!"))?;
+                let write_at = new_base + new_field_off;
+                for (i, b) in new_child.to_be_bytes().iter().enumerate() {
+                    new_buffer[write_at + i] = *b;
+                }
+            }
+        }
+
+        let new_root = new_addr_of(root_addr);
+        for (i, b) in new_root.to_be_bytes().iter().enumerate() {
+            new_buffer[2 + i] = *b;
+        }
+
+        let self_bytes_mut = unsafe { &mut *self.bytes.get() };
+        *self_bytes_mut = new_buffer;
+
+        let size_cell = unsafe { &mut *self.size.get() };
+        *size_cell = NP_Size::U32;
+
+        // every free span pointed into the old, narrower buffer; the relocated buffer
+        // is packed tight with no gaps between live nodes, so none of them carry over
+        let free_list = unsafe { &mut *self.free_list.get() };
+        free_list.clear();
+
+        Ok(())
+    }
+
+    // which free_list bucket a span of this length belongs in (spans are kept
+    // segregated so a malloc only has to scan classes >= what it needs)
+    fn size_class(len: u32) -> usize {
+        if len == 0 {
+            0
+        } else {
+            (32 - len.leading_zeros()) as usize
+        }
+    }
+
+    fn push_free_span(free_list: &mut Vec<Vec<(u32, u32)>>, addr: u32, len: u32) {
+        if addr == 0 || len == 0 {
+            return;
+        }
+
+        let class = Self::size_class(len);
+
+        while free_list.len() <= class {
+            free_list.push(Vec::new());
+        }
+
+        free_list[class].push((addr, len));
+    }
+
+    /// Release a previously `malloc`'d span back to the allocator so a later `malloc`
+    /// can reuse it instead of growing the buffer. `addr == 0` (the null sentinel) is
+    /// ignored since it was never a real allocation.
+    pub fn free(&self, addr: u32, len: u32) -> core::result::Result<(), NP_Error> {
+        self.ensure_mutable()?;
+        let free_list = unsafe { &mut *self.free_list.get() };
+        Self::push_free_span(free_list, addr, len);
+        Ok(())
+    }
+
+    /// Sort and merge adjacent/overlapping free spans so fragmentation doesn't
+    /// accumulate across many small frees. Cheap to call periodically or on demand.
+    pub fn compact_free_list(&self) {
+        let free_list = unsafe { &mut *self.free_list.get() };
+
+        let mut spans: Vec<(u32, u32)> = Vec::new();
+        for class in free_list.iter_mut() {
+            spans.extend(class.drain(..));
+        }
+
+        spans.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut merged: Vec<(u32, u32)> = Vec::new();
+        for (addr, len) in spans {
+            if let Some(&mut (last_addr, ref mut last_len)) = merged.last_mut() {
+                if addr <= last_addr + *last_len {
+                    let new_end = core::cmp::max(last_addr + *last_len, addr + len);
+                    *last_len = new_end - last_addr;
+                    continue;
+                }
+            }
+            merged.push((addr, len));
+        }
+
+        for (addr, len) in merged {
+            Self::push_free_span(free_list, addr, len);
+        }
+    }
+
+    // find the smallest free span that can satisfy `needed`, removing it from the
+    // free list and handing back any leftover remainder
+    fn alloc_from_free_list(&self, needed: u32) -> Option<u32> {
+        let free_list = unsafe { &mut *self.free_list.get() };
+
+        let mut best: Option<(usize, usize, u32, u32)> = None; // (class, index, addr, len)
+
+        for class in Self::size_class(needed)..free_list.len() {
+            for (index, &(addr, len)) in free_list[class].iter().enumerate() {
+                if len < needed {
+                    continue;
+                }
+                let is_better = match best {
+                    None => true,
+                    Some((_, _, _, best_len)) => len < best_len
+                };
+                if is_better {
+                    best = Some((class, index, addr, len));
+                }
             }
         }
+
+        let (class, index, addr, len) = best?;
+        free_list[class].remove(index);
+
+        let self_bytes = unsafe { &mut *self.bytes.get() };
+        for i in 0..needed {
+            self_bytes[(addr + i) as usize] = 0;
+        }
+
+        let remainder = len - needed;
+        if remainder > 0 {
+            Self::push_free_span(free_list, addr + needed, remainder);
+        }
+
+        Some(addr)
     }
 
     pub fn addr_size(&self) -> usize {
-        match &self.size {
+        match self.size() {
             NP_Size::U32 => MAX_SIZE_LARGE,
-            NP_Size::U16 => MAX_SIZE_SMALL
+            NP_Size::U16 => MAX_SIZE_SMALL,
+            NP_Size::VarInt => MAX_SIZE_LARGE
         }
     }
 
@@ -61,71 +635,116 @@ impl<'a> NP_Memory {
             NP_Size::U16 => {
                 new_bytes.push(1); // size key (1 for U16)
                 new_bytes.extend(0u16.to_be_bytes().to_vec()); // u16 HEAD for root pointer (starts at zero)
+            },
+            NP_Size::VarInt => {
+                new_bytes.push(2); // size key (2 for VarInt)
+                new_bytes.extend(0u32.to_be_bytes().to_vec()); // root HEAD stays a fixed u32, only inner pointers are variable-width
             }
         }
 
 
         NP_Memory {
             bytes: UnsafeCell::new(new_bytes),
-            size: size
+            #[cfg(feature = "bytes")]
+            shared: UnsafeCell::new(None),
+            free_list: UnsafeCell::new(Vec::new()),
+            auto_promote: None,
+            size: UnsafeCell::new(size)
         }
     }
 
+    /// Like `new`, but a U16 buffer that's about to cross `MAX_SIZE_SMALL` is widened
+    /// to U32 addressing in place instead of failing with "Not enough space available".
+    /// `walker` is the schema-aware pointer-graph walker `promote_to_u32` needs to
+    /// rewrite every downstream address (see `compact`'s `children` for the same shape).
+    pub fn new_with_auto_promote(capacity: Option<usize>, size: NP_Size, walker: NP_GraphWalker) -> Self {
+        let mut memory = Self::new(capacity, size);
+        memory.auto_promote = Some(walker);
+        memory
+    }
+
     pub fn malloc(&self, bytes: Vec<u8>) -> core::result::Result<u32, NP_Error> {
 
-        let self_bytes = unsafe { &mut *self.bytes.get() };
+        self.ensure_mutable()?;
 
-        let location = self_bytes.len();
+        let needed = bytes.len() as u32;
 
-        let max_sze = match self.size {
+        // reuse a freed span before growing the buffer
+        if needed > 0 {
+            if let Some(addr) = self.alloc_from_free_list(needed) {
+                let self_bytes = unsafe { &mut *self.bytes.get() };
+                let start = addr as usize;
+                for (i, byte) in bytes.iter().enumerate() {
+                    self_bytes[start + i] = *byte;
+                }
+                return Ok(addr);
+            }
+        }
+
+        let location = self.current_bytes().len();
+
+        let max_sze = match self.size() {
             NP_Size::U16 => { MAX_SIZE_SMALL },
-            NP_Size::U32 => { MAX_SIZE_LARGE }
+            NP_Size::U32 => { MAX_SIZE_LARGE },
+            NP_Size::VarInt => { MAX_SIZE_LARGE }
         };
 
-        // not enough space left?
+        // U16 buffer about to overflow: transparently widen to U32 and keep going
         if location + bytes.len() >= max_sze {
+            if let NP_Size::U16 = self.size() {
+                if self.auto_promote.is_some() {
+                    self.promote_to_u32()?;
+                    return self.malloc(bytes);
+                }
+            }
             return Err(NP_Error::new("Not enough space available in buffer!"))
         }
 
+        let self_bytes = unsafe { &mut *self.bytes.get() };
         self_bytes.extend(bytes);
         Ok(location as u32)
     }
 
-    pub fn read_bytes(&self) -> &Vec<u8> {
-        let self_bytes = unsafe { &*self.bytes.get() };
-        self_bytes
+    /// The buffer's current bytes, whichever backing (`bytes` or a frozen shared
+    /// `Bytes`) actually holds them -- see `current_bytes`.
+    pub fn read_bytes(&self) -> &[u8] {
+        self.current_bytes()
     }
 
-    pub fn write_bytes(&self) -> &mut Vec<u8> {
+    /// Mutable access to the buffer's bytes. `Result`-wrapped, unlike the rest of
+    /// this series's read path, because a frozen (shared) buffer genuinely can't
+    /// hand out a `&mut Vec<u8>` -- there's no mutable `Vec` left to borrow once
+    /// `freeze`/`from_shared` hands the data off to a refcounted `Bytes`.
+    pub fn write_bytes(&self) -> core::result::Result<&mut Vec<u8>, NP_Error> {
+        self.ensure_mutable()?;
         let self_bytes = unsafe { &mut *self.bytes.get() };
-        self_bytes
+        Ok(self_bytes)
     }
 
     pub fn ptr_size(&self, ptr: &NP_PtrKinds) -> u32 {
-        // Get the size of this pointer based it's kind
-        match self.size {
-            NP_Size::U32 => {
-                match ptr {
-                    NP_PtrKinds::None                                     =>   { 0u32 },
-                    NP_PtrKinds::Standard  { addr: _ }                   =>    { 4u32 },
-                    NP_PtrKinds::MapItem   { addr: _, key: _,  next: _ } =>    { 12u32 },
-                    NP_PtrKinds::TableItem { addr: _, i:_ ,    next: _ } =>    { 9u32 },
-                    NP_PtrKinds::ListItem  { addr: _, i:_ ,    next: _ } =>    { 10u32 }
-                }
-            },
-            NP_Size::U16 => {
-                match ptr {
-                    NP_PtrKinds::None                                     =>   { 0u32 },
-                    NP_PtrKinds::Standard  { addr: _ }                   =>    { 2u32 },
-                    NP_PtrKinds::MapItem   { addr: _, key: _,  next: _ } =>    { 6u32 },
-                    NP_PtrKinds::TableItem { addr: _, i:_ ,    next: _ } =>    { 5u32 },
-                    NP_PtrKinds::ListItem  { addr: _, i:_ ,    next: _ } =>    { 6u32 }
-                }
-            }
-        }
+        Self::ptr_size_for(self.size(), ptr)
     }
 
     pub fn blank_ptr_bytes(&self, ptr: &NP_PtrKinds) -> Vec<u8> {
+        // Under VarInt addressing a freshly reserved slot can't just be zero-filled:
+        // an all-zero field decodes as a 1-byte varint, so `set_value_address` would
+        // see a slot too narrow for any address that turns out to need more than
+        // 127, and it refuses to widen a slot in
+        // place. Reserve every address field at its worst-case width up front so a
+        // VarInt pointer never needs to relocate just because the address it ends
+        // up holding grew.
+        if let NP_Size::VarInt = self.size() {
+            let max = Self::varint_max_len();
+            return Self::field_layout(ptr).iter().fold(Vec::new(), |mut out, &(is_address, _w16, w32)| {
+                if is_address {
+                    out.extend(Self::blank_varint_field(max));
+                } else {
+                    out.extend(alloc::vec![0u8; w32 as usize]);
+                }
+                out
+            });
+        }
+
         let size = self.ptr_size(ptr);
         let mut empty_bytes = Vec::with_capacity(size as usize);
         for _x in 0..size {
@@ -134,20 +753,32 @@ impl<'a> NP_Memory {
         empty_bytes
     }
 
-    pub fn set_value_address(&self, address: u32, val: u32, kind: &NP_PtrKinds) -> NP_PtrKinds {
+    pub fn set_value_address(&self, address: u32, val: u32, kind: &NP_PtrKinds) -> core::result::Result<NP_PtrKinds, NP_Error> {
+
+        self.ensure_mutable()?;
 
-        let addr_bytes = match self.size {
+        // VarInt pointer slots aren't fixed-width, so an in-place write first reads
+        // back how many bytes are already reserved there (the existing encoding,
+        // including any zero-continuation padding from a previous write) and re-pads
+        // the new value to that same width. If `val` genuinely needs more bytes than
+        // that, this returns `NP_Error` -- the caller owns the surrounding layout and
+        // has to relocate this pointer (free the old span, malloc a wider one) first.
+        let addr_bytes = match self.size() {
             NP_Size::U32 => val.to_be_bytes().to_vec(),
-            NP_Size::U16 => (val as u16).to_be_bytes().to_vec()
+            NP_Size::U16 => (val as u16).to_be_bytes().to_vec(),
+            NP_Size::VarInt => {
+                let (_, reserved_width) = Self::decode_varint(&self.current_bytes()[address as usize..])?;
+                Self::encode_varint_into_width(val, reserved_width)?
+            }
         };
 
         let self_bytes = unsafe { &mut *self.bytes.get() };
-    
+
         for x in 0..addr_bytes.len() {
             self_bytes[(address + x as u32) as usize] = addr_bytes[x as usize];
         }
 
-        match kind {
+        Ok(match kind {
             NP_PtrKinds::None => {
                 NP_PtrKinds::None
             }
@@ -163,7 +794,7 @@ impl<'a> NP_Memory {
             NP_PtrKinds::ListItem { addr: _, i, next  } => {
                 NP_PtrKinds::ListItem { addr: val, i: *i, next: *next }
             }
-        }
+        })
     }
 
     pub fn get_1_byte(&self, address: usize) -> Option<u8> {
@@ -173,7 +804,7 @@ impl<'a> NP_Memory {
             return None;
         }
 
-        let self_bytes = unsafe { &*self.bytes.get() };
+        let self_bytes = self.current_bytes();
  
         Some(self_bytes[address])
     }
@@ -185,7 +816,7 @@ impl<'a> NP_Memory {
             return None;
         }
 
-        let self_bytes = unsafe { &*self.bytes.get() };
+        let self_bytes = self.current_bytes();
 
         if self_bytes.len() < address + 2 {
             return None;
@@ -203,7 +834,7 @@ impl<'a> NP_Memory {
             return None;
         }
 
-        let self_bytes = unsafe { &*self.bytes.get() };
+        let self_bytes = self.current_bytes();
 
         if self_bytes.len() < address + 4 {
             return None;
@@ -221,7 +852,7 @@ impl<'a> NP_Memory {
             return None;
         }
 
-        let self_bytes = unsafe { &*self.bytes.get() };
+        let self_bytes = self.current_bytes();
 
         if self_bytes.len() < address + 8 {
             return None;
@@ -239,7 +870,7 @@ impl<'a> NP_Memory {
             return None;
         }
 
-        let self_bytes = unsafe { &*self.bytes.get() };
+        let self_bytes = self.current_bytes();
 
         if self_bytes.len() < address + 16 {
             return None;
@@ -257,7 +888,7 @@ impl<'a> NP_Memory {
             return None;
         }
 
-        let self_bytes = unsafe { &*self.bytes.get() };
+        let self_bytes = self.current_bytes();
 
         if self_bytes.len() < address + 32 {
             return None;
@@ -269,6 +900,412 @@ impl<'a> NP_Memory {
     }
 
     pub fn dump(self) -> Vec<u8> {
+        #[cfg(feature = "bytes")]
+        if let Some(shared) = self.shared.into_inner() {
+            return shared.to_vec();
+        }
         self.bytes.into_inner()
     }
+
+    /// Build an `NP_Memory` directly from an I/O source instead of a fully
+    /// materialized `Vec<u8>`. Reads and validates the protocol version byte, then
+    /// the size-key byte, then the root HEAD (4 or 2 bytes depending on the size
+    /// key), then drains the remainder straight into the backing vec. Any short
+    /// read along the way comes back as a descriptive `NP_Error` instead of a panic
+    /// or an `std::io` error the caller has to translate themselves.
+    #[cfg(feature = "std")]
+    pub fn from_reader<R: std::io::Read>(r: &mut R) -> core::result::Result<Self, NP_Error> {
+
+        let map_err = |_| NP_Error::new("Truncated buffer: unexpected end of stream!");
+
+        let mut header = [0u8; 2];
+        r.read_exact(&mut header).map_err(map_err)?;
+
+        if header[0] != PROTOCOL_VERSION {
+            return Err(NP_Error::new("Unsupported protocol version!"));
+        }
+
+        let mut bytes: Vec<u8> = Vec::new();
+        bytes.extend_from_slice(&header);
+
+        match header[1] {
+            // U32 and VarInt both keep a fixed 4-byte root HEAD (see `root_addr`);
+            // only U16 narrows it to 2 bytes
+            0 | 2 => {
+                let mut head = [0u8; 4];
+                r.read_exact(&mut head).map_err(map_err)?;
+                bytes.extend_from_slice(&head);
+            },
+            _ => {
+                let mut head = [0u8; 2];
+                r.read_exact(&mut head).map_err(map_err)?;
+                bytes.extend_from_slice(&head);
+            }
+        };
+
+        r.read_to_end(&mut bytes).map_err(map_err)?;
+
+        Ok(Self::existing(bytes))
+    }
+
+    /// Serialize this buffer straight to an I/O sink, writing whichever backing
+    /// (`shared` or owned `Vec`) it currently holds without an intermediate
+    /// `dump()` allocation.
+    #[cfg(feature = "std")]
+    pub fn write_to<W: std::io::Write>(&self, w: &mut W) -> core::result::Result<(), NP_Error> {
+        w.write_all(self.current_bytes()).map_err(|_| NP_Error::new("Failed writing buffer to sink!"))
+    }
+
+    /// Walk the live pointer graph reachable from the root HEAD and build a minimal
+    /// buffer containing only the bytes still in use, discarding anything left behind
+    /// by prior edits/deletes that the free-list allocator hasn't reclaimed.
+    ///
+    /// `NP_Memory` only knows raw bytes, not schema, so the caller supplies `children`:
+    /// given the pointer currently being visited and the address it lives at, it returns
+    /// every `(field_offset, addr, kind, span_len)` reachable from it — `field_offset` is
+    /// where, inside that pointer's own span, the address is encoded (so it can be
+    /// rewritten once the child's new location is known), and `span_len` is how many
+    /// bytes the child occupies (its pointer fields plus any inline value payload).
+    pub fn compact<F>(&self, mut children: F) -> core::result::Result<Vec<u8>, NP_Error>
+        where F: FnMut(&Self, u32, &NP_PtrKinds, u32) -> Vec<(usize, u32, NP_PtrKinds, u32)>
+    {
+
+        let root_addr = self.root_addr();
+        let root_kind = NP_PtrKinds::Standard { addr: root_addr };
+
+        // the header's root HEAD is always fixed-width (4 bytes for U32 and VarInt,
+        // 2 for U16) even in VarInt mode, matching `root_addr`/`new` -- only pointers
+        // inside the data region are variable-width
+        let head_width = match self.size() {
+            NP_Size::U32 | NP_Size::VarInt => 4,
+            NP_Size::U16 => 2
+        };
+        let header_len = 2 + head_width;
+        // the root node's own span isn't fixed-width like the HEAD field that points
+        // at it -- under VarInt addressing it's the reserved (worst-case) width
+        // `blank_ptr_bytes` gives every Standard pointer, not the tight encoding of
+        // whatever address it happens to hold, so size it the same way every other
+        // node's span is sized
+        let root_span = Self::ptr_size_for(self.size(), &root_kind);
+
+        // depth-first mark phase (`frontier` is a stack): every address we've already queued/visited, plus
+        // each node's outgoing pointer fields, captured once so we don't call `children`
+        // twice for the same node.
+        let mut visited: Vec<u32> = Vec::new();
+        let mut order: Vec<(u32, u32)> = Vec::new(); // (addr, span_len), in discovery order
+        let mut fields_of: Vec<(u32, Vec<(usize, u32, NP_PtrKinds, u32)>)> = Vec::new();
+
+        let mut frontier: Vec<(u32, NP_PtrKinds, u32)> = Vec::new();
+        if root_addr != 0 {
+            frontier.push((root_addr, root_kind, root_span));
+        }
+
+        while let Some((addr, kind, span_len)) = frontier.pop() {
+            if addr == 0 || visited.contains(&addr) {
+                continue;
+            }
+            visited.push(addr);
+            order.push((addr, span_len));
+
+            let node_fields = children(self, addr, &kind, span_len);
+            for &(_, child_addr, ref child_kind, child_span) in node_fields.iter() {
+                if child_addr != 0 && !visited.contains(&child_addr) {
+                    frontier.push((child_addr, child_kind.clone(), child_span));
+                }
+            }
+            fields_of.push((addr, node_fields));
+        }
+
+        // sweep phase: assign every live node a new address, packed back-to-back
+        // right after the header
+        let mut relocated: Vec<(u32, u32)> = Vec::new(); // old addr -> new addr
+        let mut offset = header_len as u32;
+        for &(addr, span_len) in order.iter() {
+            relocated.push((addr, offset));
+            offset += span_len;
+        }
+
+        let new_addr_of = |addr: u32| -> u32 {
+            if addr == 0 {
+                return 0;
+            }
+            relocated.iter().find(|(old, _)| *old == addr).map(|(_, new)| *new).unwrap_or(0)
+        };
+
+        let self_bytes = self.current_bytes();
+        let mut new_buffer: Vec<u8> = Vec::with_capacity(offset as usize);
+        for _ in 0..header_len {
+            new_buffer.push(0);
+        }
+
+        for &(addr, span_len) in order.iter() {
+            let start = addr as usize;
+            let end = start + span_len as usize;
+            new_buffer.extend_from_slice(&self_bytes[start..end]);
+        }
+
+        // rewrite each relocated node's embedded addr/next/key fields to point at the
+        // new locations, using this buffer's normal address encoding
+        for (addr, node_fields) in fields_of.iter() {
+            let new_base = new_addr_of(*addr) as usize;
+            for &(field_offset, child_addr, _, _) in node_fields.iter() {
+                let new_child = new_addr_of(child_addr);
+                let write_at = new_base + field_offset;
+                // VarInt fields keep whatever width they already have in the freshly
+                // copied `new_buffer` (the original on-disk width, padding included);
+                // a relocated address that needs more bytes than that is an error --
+                // `compact` only repacks live nodes, it doesn't resize their fields.
+                let addr_bytes = match self.size() {
+                    NP_Size::U32 => new_child.to_be_bytes().to_vec(),
+                    NP_Size::U16 => (new_child as u16).to_be_bytes().to_vec(),
+                    NP_Size::VarInt => {
+                        let (_, reserved_width) = Self::decode_varint(&new_buffer[write_at..])?;
+                        Self::encode_varint_into_width(new_child, reserved_width)?
+                    }
+                };
+                for (i, b) in addr_bytes.iter().enumerate() {
+                    new_buffer[write_at + i] = *b;
+                }
+            }
+        }
+
+        // regenerate the header: protocol version is unchanged, size key matches the
+        // current addressing mode, and HEAD points at the relocated root
+        new_buffer[0] = self_bytes[0];
+        new_buffer[1] = match self.size() {
+            NP_Size::U32 => 0,
+            NP_Size::U16 => 1,
+            NP_Size::VarInt => 2
+        };
+
+        // root HEAD is a fixed u32 under U32 and VarInt, a fixed u16 under U16
+        let new_root = new_addr_of(root_addr);
+        let root_bytes = match self.size() {
+            NP_Size::U32 | NP_Size::VarInt => new_root.to_be_bytes().to_vec(),
+            NP_Size::U16 => (new_root as u16).to_be_bytes().to_vec()
+        };
+        for (i, b) in root_bytes.iter().enumerate() {
+            new_buffer[2 + i] = *b;
+        }
+
+        Ok(new_buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn free_and_malloc_reuses_freed_span() {
+        let mem = NP_Memory::new(Some(64), NP_Size::U32);
+
+        let first = mem.malloc(alloc::vec![1, 2, 3, 4]).unwrap();
+        mem.free(first, 4).unwrap();
+
+        // a same-size malloc right after should come back out of the free list
+        // instead of growing the buffer
+        let before_len = mem.read_bytes().len();
+        let second = mem.malloc(alloc::vec![9, 9, 9, 9]).unwrap();
+
+        assert_eq!(first, second);
+        assert_eq!(mem.read_bytes().len(), before_len);
+        assert_eq!(mem.read_bytes()[second as usize..second as usize + 4].to_vec(), alloc::vec![9, 9, 9, 9]);
+    }
+
+    #[test]
+    fn free_list_splits_remainder_on_reuse() {
+        let mem = NP_Memory::new(Some(64), NP_Size::U32);
+
+        let span = mem.malloc(alloc::vec![0; 10]).unwrap();
+        mem.free(span, 10).unwrap();
+
+        // asking for less than the freed span should reuse its head and keep the
+        // leftover tail available for a later malloc
+        let reused = mem.malloc(alloc::vec![0; 4]).unwrap();
+        assert_eq!(reused, span);
+
+        let leftover = mem.malloc(alloc::vec![0; 6]).unwrap();
+        assert_eq!(leftover, span + 4);
+    }
+
+    #[test]
+    fn compact_drops_orphaned_bytes_and_keeps_reachable_data() {
+        let mem = NP_Memory::new(Some(64), NP_Size::U32);
+
+        // orphan: allocated but never linked into the root graph and never freed --
+        // exactly the garbage compact() exists to reclaim
+        mem.malloc(alloc::vec![0xAA, 0xAA, 0xAA, 0xAA]).unwrap();
+
+        // the live node: reachable from the root HEAD, no children of its own
+        let root_addr = mem.malloc(alloc::vec![7, 7, 7, 7]).unwrap();
+        mem.write_bytes().unwrap()[2..6].copy_from_slice(&root_addr.to_be_bytes());
+
+        let compacted = mem.compact(|_mem, _addr, _kind, _span_len| Vec::new()).unwrap();
+
+        // header (6 bytes) + one 4-byte live node; the orphan is gone entirely
+        assert_eq!(compacted.len(), 6 + 4);
+        let new_root = u32::from_be_bytes([compacted[2], compacted[3], compacted[4], compacted[5]]);
+        assert_eq!(compacted[new_root as usize..new_root as usize + 4].to_vec(), alloc::vec![7, 7, 7, 7]);
+    }
+
+    #[test]
+    fn compact_sizes_varint_root_span_from_the_reserved_width() {
+        // Same shape as compact_drops_orphaned_bytes_and_keeps_reachable_data, but
+        // under VarInt addressing, where the root node's own span isn't a fixed
+        // width -- it has to agree with what `blank_ptr_bytes` actually reserves for
+        // a Standard pointer's one address field, or this copies the wrong number of
+        // bytes for the root node.
+        let mem = NP_Memory::new(Some(64), NP_Size::VarInt);
+
+        // orphan: allocated but never linked into the root graph
+        mem.malloc(alloc::vec![0xAA, 0xAA, 0xAA, 0xAA, 0xAA]).unwrap();
+
+        // the live root node, reserved at its worst-case VarInt width (5 bytes for a
+        // single address field), no children of its own
+        let root_addr = mem.malloc(alloc::vec![7, 7, 7, 7, 7]).unwrap();
+        mem.write_bytes().unwrap()[2..6].copy_from_slice(&root_addr.to_be_bytes());
+
+        let compacted = mem.compact(|_mem, _addr, _kind, _span_len| Vec::new()).unwrap();
+
+        // header (2 + 4-byte HEAD) + one 5-byte live node; the orphan is gone
+        assert_eq!(compacted.len(), 6 + 5);
+        let new_root = u32::from_be_bytes([compacted[2], compacted[3], compacted[4], compacted[5]]);
+        assert_eq!(compacted[new_root as usize..new_root as usize + 5].to_vec(), alloc::vec![7, 7, 7, 7, 7]);
+    }
+
+    #[test]
+    fn promote_to_u32_relocates_every_field_and_keeps_payloads() {
+        // root HEAD -> Standard pointer -> MapItem{addr, key, next} -(next)-> MapItem
+        // -(key)-> one leaf. A MapItem has fields past the first (key, then next),
+        // which is exactly what the U16->U32 field-offset mapping has to get right.
+        let walker: NP_GraphWalker = alloc::boxed::Box::new(|mem, addr, kind, _span_len| {
+            let bytes = mem.read_bytes();
+            match kind {
+                NP_PtrKinds::Standard { .. } => {
+                    let target = u16::from_be_bytes([bytes[addr as usize], bytes[addr as usize + 1]]) as u32;
+                    alloc::vec![(0usize, target, NP_PtrKinds::MapItem { addr: 0, key: 0, next: 0 }, 6u32)]
+                },
+                // Per the `NP_GraphWalker` convention, a null (0) address field is
+                // omitted from the returned list entirely -- it isn't padded in with
+                // a placeholder entry. A node can therefore have a live field past
+                // one or more null ones (e.g. no value yet, but a live `next` chain),
+                // which is exactly what exercises the field-offset mapping below.
+                NP_PtrKinds::MapItem { .. } => {
+                    let a = u16::from_be_bytes([bytes[addr as usize], bytes[addr as usize + 1]]) as u32;
+                    let k = u16::from_be_bytes([bytes[addr as usize + 2], bytes[addr as usize + 3]]) as u32;
+                    let n = u16::from_be_bytes([bytes[addr as usize + 4], bytes[addr as usize + 5]]) as u32;
+                    let mut out = Vec::new();
+                    if a != 0 { out.push((0usize, a, NP_PtrKinds::None, 2u32)); }
+                    if k != 0 { out.push((2usize, k, NP_PtrKinds::None, 3u32)); }
+                    if n != 0 { out.push((4usize, n, NP_PtrKinds::MapItem { addr: 0, key: 0, next: 0 }, 6u32)); }
+                    out
+                },
+                _ => Vec::new()
+            }
+        });
+
+        let mem = NP_Memory::new_with_auto_promote(Some(128), NP_Size::U16, walker);
+
+        let key_addr = mem.malloc(alloc::vec![0x01, 0x02, 0x03]).unwrap();
+
+        // map2: no value, no next -- only `key` is live (addr/next null and omitted
+        // by the walker). Positional indexing would patch this `key` into map2's
+        // own `addr` slot instead of its `key` slot.
+        let mut map2_bytes = Vec::new();
+        map2_bytes.extend(0u16.to_be_bytes()); // addr
+        map2_bytes.extend((key_addr as u16).to_be_bytes()); // key
+        map2_bytes.extend(0u16.to_be_bytes()); // next
+        let map2_addr = mem.malloc(map2_bytes).unwrap();
+
+        // map1: no value, no key -- only `next` (pointing at map2) is live.
+        let mut map1_bytes = Vec::new();
+        map1_bytes.extend(0u16.to_be_bytes()); // addr
+        map1_bytes.extend(0u16.to_be_bytes()); // key
+        map1_bytes.extend((map2_addr as u16).to_be_bytes()); // next
+        let map1_addr = mem.malloc(map1_bytes).unwrap();
+
+        let std_addr = mem.malloc((map1_addr as u16).to_be_bytes().to_vec()).unwrap();
+        mem.write_bytes().unwrap()[2..4].copy_from_slice(&(std_addr as u16).to_be_bytes());
+
+        mem.promote_to_u32().unwrap();
+
+        assert!(matches!(mem.size(), NP_Size::U32));
+
+        let new_root = mem.root_addr();
+        let new_map1_addr = u32::from_be_bytes(*mem.get_4_bytes(new_root as usize).unwrap());
+
+        let map1_addr_field = u32::from_be_bytes(*mem.get_4_bytes(new_map1_addr as usize).unwrap());
+        let map1_key_field = u32::from_be_bytes(*mem.get_4_bytes(new_map1_addr as usize + 4).unwrap());
+        let new_map2_addr = u32::from_be_bytes(*mem.get_4_bytes(new_map1_addr as usize + 8).unwrap());
+
+        assert_eq!(map1_addr_field, 0);
+        assert_eq!(map1_key_field, 0);
+        assert_ne!(new_map2_addr, 0);
+
+        let map2_addr_field = u32::from_be_bytes(*mem.get_4_bytes(new_map2_addr as usize).unwrap());
+        let new_key_addr = u32::from_be_bytes(*mem.get_4_bytes(new_map2_addr as usize + 4).unwrap());
+        let map2_next_field = u32::from_be_bytes(*mem.get_4_bytes(new_map2_addr as usize + 8).unwrap());
+
+        assert_eq!(map2_addr_field, 0);
+        assert_eq!(map2_next_field, 0);
+        assert_eq!(mem.read_bytes()[new_key_addr as usize..new_key_addr as usize + 3].to_vec(), alloc::vec![0x01, 0x02, 0x03]);
+    }
+
+    #[test]
+    fn promote_to_u32_errors_on_walker_field_offset_mismatch() {
+        // a walker that reports a field_offset no address field of `Standard`
+        // actually has (its only field sits at offset 0) -- promote_to_u32 must
+        // error instead of silently patching the wrong byte range
+        let walker: NP_GraphWalker = alloc::boxed::Box::new(|mem, addr, kind, _span_len| {
+            let bytes = mem.read_bytes();
+            match kind {
+                NP_PtrKinds::Standard { .. } => {
+                    let target = u16::from_be_bytes([bytes[addr as usize], bytes[addr as usize + 1]]) as u32;
+                    alloc::vec![(99usize, target, NP_PtrKinds::None, 2u32)]
+                },
+                _ => Vec::new()
+            }
+        });
+
+        let mem = NP_Memory::new_with_auto_promote(Some(64), NP_Size::U16, walker);
+
+        let leaf_addr = mem.malloc(alloc::vec![0xAA, 0xBB]).unwrap();
+        let std_addr = mem.malloc((leaf_addr as u16).to_be_bytes().to_vec()).unwrap();
+        mem.write_bytes().unwrap()[2..4].copy_from_slice(&(std_addr as u16).to_be_bytes());
+
+        assert!(mem.promote_to_u32().is_err());
+    }
+
+    #[test]
+    fn varint_encode_decode_round_trips() {
+        for value in [0u32, 1, 127, 128, 300, 0x3FFF, 0x4000, core::u32::MAX] {
+            let encoded = NP_Memory::encode_varint(value);
+            assert_eq!(encoded.len() as u32, NP_Memory::varint_len(value));
+
+            let (decoded, consumed) = NP_Memory::decode_varint(&encoded).unwrap();
+            assert_eq!(decoded, value);
+            assert_eq!(consumed, encoded.len());
+        }
+    }
+
+    #[test]
+    fn varint_into_width_pads_and_round_trips() {
+        let padded = NP_Memory::encode_varint_into_width(1, 3).unwrap();
+        assert_eq!(padded.len(), 3);
+
+        let (decoded, consumed) = NP_Memory::decode_varint(&padded).unwrap();
+        assert_eq!(decoded, 1);
+        assert_eq!(consumed, 3);
+
+        // a value that genuinely needs more bytes than the reserved width is an error
+        assert!(NP_Memory::encode_varint_into_width(u32::from(u16::MAX) + 1, 1).is_err());
+    }
+
+    #[test]
+    fn decode_varint_errors_on_unterminated_input() {
+        // every byte has the continuation bit set, so this never terminates
+        let unterminated = [0x80u8; 10];
+        assert!(NP_Memory::decode_varint(&unterminated).is_err());
+    }
 }
\ No newline at end of file